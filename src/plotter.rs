@@ -1,4 +1,7 @@
+extern crate ctrlc;
 extern crate humanize_rs;
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate pbr;
 extern crate raw_cpuid;
 extern crate rayon;
@@ -12,10 +15,14 @@ use chan;
 use core_affinity;
 #[cfg(feature = "opencl")]
 use ocl::gpu_get_info;
+use rayon::prelude::*;
 use scheduler::create_scheduler_thread;
 use std::cmp::{max, min};
+use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use stopwatch::Stopwatch;
@@ -50,27 +57,55 @@ pub struct PlotterTask {
     pub cpu_threads: u8,
     pub gpus: Option<Vec<String>>,
     pub direct_io: bool,
-    pub async_io: bool,
     pub quiet: bool,
     pub benchmark: bool,
     pub zcb: bool,
+    pub pipeline_depth: u64,
+    pub numa_node: Option<usize>,
 }
 
 pub struct Buffer {
     data: Arc<Mutex<Vec<u8>>>,
+    // bytes actually hashed on the most recent fill; less than capacity on the last batch
+    filled: u64,
 }
 
 impl Buffer {
     fn new(buffer_size: usize) -> Self {
-        let data = vec![1u8; buffer_size];
+        // left uninitialized; first_touch() does the actual zero-fill
+        let mut data = Vec::with_capacity(buffer_size);
+        unsafe {
+            data.set_len(buffer_size);
+        }
         Buffer {
             data: Arc::new(Mutex::new(data)),
+            filled: buffer_size as u64,
         }
     }
 
+    // zero-fills the buffer through the (possibly NUMA-pinned) pool, faulting pages in local to it
+    fn first_touch(&self, pool: &rayon::ThreadPool) {
+        let mut data = self.data.lock().unwrap();
+        pool.install(|| {
+            data.par_chunks_mut(4096).for_each(|chunk| {
+                for byte in chunk.iter_mut() {
+                    *byte = 0;
+                }
+            });
+        });
+    }
+
     pub fn get_buffer(&self) -> Arc<Mutex<Vec<u8>>> {
         self.data.clone()
     }
+
+    pub fn filled(&self) -> u64 {
+        self.filled
+    }
+
+    pub fn set_filled(&mut self, filled: u64) {
+        self.filled = filled;
+    }
 }
 
 impl Plotter {
@@ -79,6 +114,16 @@ impl Plotter {
     }
 
     pub fn run(self, mut task: PlotterTask) {
+        // installed up front so a Ctrl-C during preallocation or setup is still observed
+        // by the scheduler/writer once they start
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        {
+            let stop_flag = stop_flag.clone();
+            ctrlc::set_handler(move || {
+                stop_flag.store(true, Ordering::SeqCst);
+            }).expect("Error installing Ctrl-C handler");
+        }
+
         let sys = System::new();
         let cpuid = CpuId::new();
         let cpu_name = cpuid.get_extended_function_info().unwrap();
@@ -87,6 +132,7 @@ impl Plotter {
         let memory = sys.memory().unwrap();;
 
         let simd_ext = detect_simd();
+        let numa_topology = detect_numa_topology(task.numa_node, task.quiet);
 
         if !task.quiet {
             println!("Engraver {} - PoC2 Plotter\n", crate_version!());
@@ -107,6 +153,21 @@ impl Plotter {
             );
         }
 
+        if !task.quiet {
+            if let Some(ref topo) = numa_topology {
+                println!(
+                    "NUMA: node {} [{} cores: {}]",
+                    topo.node,
+                    topo.core_ids.len(),
+                    topo.core_ids
+                        .iter()
+                        .map(|c| c.id.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                );
+            }
+        }
+
         #[cfg(not(feature = "opencl"))]
         let gpu_mem_needed = 0u64;
         #[cfg(feature = "opencl")]
@@ -136,13 +197,26 @@ impl Plotter {
         // align number of nonces with sector size if direct i/o
         let mut rounded_nonces_to_sector_size = false;
         let mut nonces_per_sector = 1;
+        let mut direct_io_filesystem = String::from("n/a");
         if task.direct_io {
             let sector_size = get_sector_size(&task.output_path);
-            nonces_per_sector = sector_size / SCOOP_SIZE;
-            if task.nonces % nonces_per_sector > 0 {
-                rounded_nonces_to_sector_size = true;
-                task.nonces /= nonces_per_sector;
-                task.nonces *= nonces_per_sector;
+            let probe = probe_direct_io(&task.output_path, sector_size);
+            direct_io_filesystem = probe.filesystem;
+            if probe.active {
+                nonces_per_sector = probe.nonces_per_sector;
+                if task.nonces % nonces_per_sector > 0 {
+                    rounded_nonces_to_sector_size = true;
+                    task.nonces /= nonces_per_sector;
+                    task.nonces *= nonces_per_sector;
+                }
+            } else {
+                if !task.quiet {
+                    println!(
+                        "Warning: O_DIRECT rejected on {} ({}), falling back to buffered i/o",
+                        task.output_path, direct_io_filesystem
+                    );
+                }
+                task.direct_io = false;
             }
         }
 
@@ -206,6 +280,12 @@ impl Plotter {
                     &""
                 }
             );
+            println!(
+                "Direct I/O:  {} [filesystem={}, alignment={} bytes]",
+                if task.direct_io { "active" } else { "inactive" },
+                direct_io_filesystem,
+                nonces_per_sector * SCOOP_SIZE
+            );
         }
 
         if !task.quiet {
@@ -250,14 +330,49 @@ impl Plotter {
             }
         }
 
-        // determine buffer size
-        let num_buffer = if task.async_io { 2 } else { 1 };
+        // holds the precise completed-nonce offset the writer checkpointed at, so the
+        // interrupt message below can report exactly where plotting left off
+        let resume_offset = Arc::new(AtomicU64::new(progress));
+
+        let task = Arc::new(task);
+
+        // hi bold! might make this optional in future releases.
+        let thread_pinning = true;
+        let core_ids = match &numa_topology {
+            Some(topo) => topo.core_ids.clone(),
+            None => {
+                if thread_pinning {
+                    core_affinity::get_core_ids().unwrap()
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(task.cpu_threads as usize)
+            .start_handler(move |id| {
+                if thread_pinning {
+                    #[cfg(not(windows))]
+                    let core_id = core_ids[id % core_ids.len()];
+                    #[cfg(not(windows))]
+                    core_affinity::set_for_current(core_id);
+                    #[cfg(windows)]
+                    set_thread_ideal_processor(id % core_ids.len());
+                }
+            }).build()
+            .unwrap();
+
+        // the pipeline ring holds `pipeline_depth` buffers of mem/pipeline_depth each
+        let num_buffer = task.pipeline_depth;
         let buffer_size = mem / num_buffer;
         let (tx_empty_buffers, rx_empty_buffers) = chan::bounded(num_buffer as usize);
         let (tx_full_buffers, rx_full_buffers) = chan::bounded(num_buffer as usize);
 
         for _ in 0..num_buffer {
             let buffer = Buffer::new(buffer_size as usize);
+            // always zero-fill, since the buffer is allocated uninitialized
+            buffer.first_touch(&pool);
             tx_empty_buffers.send(buffer);
         }
 
@@ -299,36 +414,15 @@ impl Plotter {
             }
         }
 
-        let task = Arc::new(task);
-
-        // hi bold! might make this optional in future releases.
-        let thread_pinning = true;
-        let core_ids = if thread_pinning {
-            core_affinity::get_core_ids().unwrap()
-        } else {
-            Vec::new()
-        };
-
         let hasher = thread::spawn({
             create_scheduler_thread(
                 task.clone(),
-                rayon::ThreadPoolBuilder::new()
-                    .num_threads(task.cpu_threads as usize)
-                    .start_handler(move |id| {
-                        if thread_pinning {
-                            #[cfg(not(windows))]
-                            let core_id = core_ids[id % core_ids.len()];
-                            #[cfg(not(windows))]
-                            core_affinity::set_for_current(core_id);
-                            #[cfg(windows)]
-                            set_thread_ideal_processor(id % core_ids.len());
-                        }
-                    }).build()
-                    .unwrap(),
+                pool,
                 progress,
                 p1x,
                 rx_empty_buffers.clone(),
                 tx_full_buffers.clone(),
+                stop_flag.clone(),
                 simd_ext,
             )
         });
@@ -336,10 +430,13 @@ impl Plotter {
         let writer = thread::spawn({
             create_writer_thread(
                 task.clone(),
+                file.clone(),
                 progress,
                 p2x,
                 rx_full_buffers.clone(),
                 tx_empty_buffers.clone(),
+                stop_flag.clone(),
+                resume_offset.clone(),
             )
         });
 
@@ -349,6 +446,14 @@ impl Plotter {
         writer.join().unwrap();
         hasher.join().unwrap();
 
+        if stop_flag.load(Ordering::SeqCst) {
+            println!(
+                "\nInterrupted — resume from nonce offset {}",
+                resume_offset.load(Ordering::SeqCst)
+            );
+            process::exit(0);
+        }
+
         let elapsed = sw.elapsed_ms() as u64;
         let hours = elapsed / 1000 / 60 / 60;
         let minutes = elapsed / 1000 / 60 - hours * 60;
@@ -375,6 +480,15 @@ fn calculate_mem_to_use(
     gpu: bool,
     gpu_mem_needed: u64,
 ) -> Result<u64, &'static str> {
+    if task.pipeline_depth == 0 {
+        println!(
+            "Error: --pipeline-depth must be at least 1, input={}",
+            task.pipeline_depth
+        );
+        println!("Shutting down...");
+        return Err("invalid pipeline depth");
+    }
+
     let plotsize = task.nonces * NONCE_SIZE;
 
     let mut mem = match task.mem.parse::<Bytes>() {
@@ -416,8 +530,8 @@ fn calculate_mem_to_use(
     // don't exceed free memory and leave some elbow room 1-1000/1024
     mem = min(mem, (memory.free.as_usize() as u64 - gpu_mem_needed) * 1000 / 1024);
 
-    // rounding single/double buffer
-    let num_buffer = if task.async_io { 2 } else { 1 };
+    // rounding to the pipeline depth's buffer count
+    let num_buffer = task.pipeline_depth;
     mem /= num_buffer * NONCE_SIZE * nonces_per_sector;
     mem *= num_buffer * NONCE_SIZE * nonces_per_sector;
 
@@ -439,3 +553,294 @@ fn detect_simd() -> String {
         String::from("")
     }
 }
+
+struct NumaTopology {
+    node: usize,
+    core_ids: Vec<core_affinity::CoreId>,
+}
+
+// picks the NUMA node to pin the hashing pool and buffers to: the user's choice, or on a
+// multi-node box, whichever node currently has the most free RAM. Single-node machines
+// (or platforms without sysfs NUMA info) opt out entirely and fall through to the
+// existing round-robin core pinning.
+#[cfg(target_os = "linux")]
+fn detect_numa_topology(requested_node: Option<usize>, quiet: bool) -> Option<NumaTopology> {
+    let topology = resolve_numa_topology(requested_node);
+    if topology.is_none() && !quiet {
+        if let Some(n) = requested_node {
+            println!(
+                "Warning: --numa-node {} could not be resolved, falling back to default thread pinning",
+                n
+            );
+        }
+    }
+    topology
+}
+
+#[cfg(target_os = "linux")]
+fn resolve_numa_topology(requested_node: Option<usize>) -> Option<NumaTopology> {
+    let mut nodes: Vec<usize> = fs::read_dir("/sys/devices/system/node")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| {
+            if name.starts_with("node") {
+                name[4..].parse::<usize>().ok()
+            } else {
+                None
+            }
+        })
+        .collect();
+    nodes.sort();
+
+    if nodes.len() < 2 {
+        return None;
+    }
+
+    let node = match requested_node {
+        Some(n) => n,
+        None => *nodes
+            .iter()
+            .max_by_key(|&&n| numa_node_free_bytes(n).unwrap_or(0))?,
+    };
+
+    let cpu_list = fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node)).ok()?;
+    let node_cpus = parse_cpu_list(&cpu_list);
+
+    let core_ids: Vec<core_affinity::CoreId> = core_affinity::get_core_ids()?
+        .into_iter()
+        .filter(|c| node_cpus.contains(&c.id))
+        .collect();
+
+    if core_ids.is_empty() {
+        None
+    } else {
+        Some(NumaTopology { node, core_ids })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn numa_node_free_bytes(node: usize) -> Option<u64> {
+    let meminfo = fs::read_to_string(format!("/sys/devices/system/node/node{}/meminfo", node)).ok()?;
+    parse_meminfo_free_bytes(&meminfo)
+}
+
+fn parse_meminfo_free_bytes(meminfo: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(2) != Some(&"MemFree:") {
+            return None;
+        }
+        fields.get(3)?.parse::<u64>().ok().map(|kb| kb * 1024)
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for part in list.trim().split(',') {
+        if part.is_empty() {
+            continue;
+        }
+        if let Some(idx) = part.find('-') {
+            let (start, end) = (&part[..idx], &part[idx + 1..]);
+            if let (Ok(s), Ok(e)) = (start.parse::<usize>(), end.parse::<usize>()) {
+                cpus.extend(s..=e);
+            }
+        } else if let Ok(c) = part.parse::<usize>() {
+            cpus.push(c);
+        }
+    }
+    cpus
+}
+
+#[cfg(not(target_os = "linux"))]
+fn detect_numa_topology(_requested_node: Option<usize>, _quiet: bool) -> Option<NumaTopology> {
+    None
+}
+
+struct DirectIoProbe {
+    active: bool,
+    nonces_per_sector: u64,
+    filesystem: String,
+}
+
+// a few MiB is enough to amortize file-create/metadata syscall overhead and reflect
+// real sequential-write throughput, rather than timing a single tiny aligned write
+const PROBE_WRITE_BYTES: u64 = 4 * 1024 * 1024;
+
+// attempts a sector-aligned O_DIRECT write to a temp file in the target directory,
+// analogous to trying a sequence of mount modes and keeping whichever works: if O_DIRECT
+// itself is rejected (tmpfs, some network/overlay mounts) we fall back to buffered i/o
+// instead of aborting, and otherwise time a few candidate alignments and keep the fastest
+fn probe_direct_io(output_path: &str, sector_size: u64) -> DirectIoProbe {
+    let filesystem = detect_filesystem(output_path);
+
+    // a filesystem reporting a bogus (zero or non-power-of-two) sector size is exactly
+    // the "behaves unpredictably" case this probe exists to catch; abstain rather than
+    // risk an alignment panic below
+    if sector_size == 0 || sector_size & (sector_size - 1) != 0 {
+        return DirectIoProbe {
+            active: false,
+            nonces_per_sector: 1,
+            filesystem,
+        };
+    }
+
+    let probe_path =
+        Path::new(output_path).join(format!(".engraver_direct_io_probe_{}", process::id()));
+    let candidates = [sector_size, sector_size * 2, sector_size * 4, sector_size * 8];
+
+    let mut best: Option<(u64, i64)> = None;
+    for &alignment in &candidates {
+        if let Some(elapsed) = time_direct_write(&probe_path, alignment as usize) {
+            if best.map_or(true, |(_, best_elapsed)| elapsed < best_elapsed) {
+                best = Some((alignment, elapsed));
+            }
+        }
+    }
+    let _ = fs::remove_file(&probe_path);
+
+    match best {
+        Some((alignment, _)) => DirectIoProbe {
+            active: true,
+            nonces_per_sector: alignment / SCOOP_SIZE,
+            filesystem,
+        },
+        None => DirectIoProbe {
+            active: false,
+            nonces_per_sector: 1,
+            filesystem,
+        },
+    }
+}
+
+// opens with O_DIRECT and times a PROBE_WRITE_BYTES-sized sequential write, done as
+// repeated alignment-sized chunks; returns None if O_DIRECT (or the write itself) fails
+#[cfg(target_os = "linux")]
+fn time_direct_write(path: &Path, alignment: usize) -> Option<i64> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+        .ok()?;
+
+    // O_DIRECT requires the buffer's address, not just the write length, to be
+    // sector-aligned, which a plain Vec's allocator can't guarantee
+    let buf = AlignedBuffer::new(alignment, alignment);
+    let chunks = (PROBE_WRITE_BYTES as usize / alignment).max(1);
+
+    let sw = Stopwatch::start_new();
+    for _ in 0..chunks {
+        file.write_all(buf.as_slice()).ok()?;
+    }
+    file.sync_all().ok()?;
+    Some(sw.elapsed_ms())
+}
+
+#[cfg(target_os = "linux")]
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: std::alloc::Layout,
+}
+
+#[cfg(target_os = "linux")]
+impl AlignedBuffer {
+    fn new(size: usize, align: usize) -> Self {
+        let layout =
+            std::alloc::Layout::from_size_align(size, align).expect("invalid O_DIRECT alignment");
+        let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        AlignedBuffer { ptr, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn time_direct_write(_path: &Path, _alignment: usize) -> Option<i64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn detect_filesystem(output_path: &str) -> String {
+    let canonical = fs::canonicalize(output_path).unwrap_or_else(|_| Path::new(output_path).to_path_buf());
+    let mounts = match fs::read_to_string("/proc/mounts") {
+        Ok(m) => m,
+        Err(_) => return String::from("unknown"),
+    };
+
+    let mut best: Option<(usize, String)> = None;
+    for line in mounts.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            continue;
+        }
+        let mount_point = fields[1];
+        let is_longer = best.as_ref().map_or(true, |(len, _)| mount_point.len() > *len);
+        if mount_matches(canonical.to_string_lossy().as_ref(), mount_point) && is_longer {
+            best = Some((mount_point.len(), fields[2].to_string()));
+        }
+    }
+
+    best.map(|(_, fstype)| fstype).unwrap_or_else(|| String::from("unknown"))
+}
+
+// true if `path` is at or under `mount_point`, matching on full path segments so e.g.
+// mount point "/mnt/data" doesn't falsely match path "/mnt/data2/plots"
+#[cfg(target_os = "linux")]
+fn mount_matches(path: &str, mount_point: &str) -> bool {
+    path == mount_point || path.starts_with(&format!("{}/", mount_point.trim_end_matches('/')))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn detect_filesystem(_output_path: &str) -> String {
+    String::from("unknown")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mount_matches_requires_full_path_segment() {
+        assert!(mount_matches("/mnt/data", "/mnt/data"));
+        assert!(mount_matches("/mnt/data/plots", "/mnt/data"));
+        assert!(!mount_matches("/mnt/data2/plots", "/mnt/data"));
+        assert!(mount_matches("/", "/"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_cpu_list_handles_ranges_and_singles() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpu_list(""), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn parse_meminfo_free_bytes_reads_memfree_line() {
+        let meminfo = "Node 0 MemTotal:       16777216 kB\nNode 0 MemFree:        4194304 kB\n";
+        assert_eq!(parse_meminfo_free_bytes(meminfo), Some(4194304 * 1024));
+    }
+
+    #[test]
+    fn parse_meminfo_free_bytes_missing_field_is_none() {
+        assert_eq!(parse_meminfo_free_bytes("Node 0 MemTotal: 16777216 kB\n"), None);
+    }
+}
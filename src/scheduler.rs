@@ -0,0 +1,84 @@
+extern crate pbr;
+extern crate rayon;
+
+use self::pbr::{Pipe, ProgressBar};
+use chan;
+use plotter::{Buffer, PlotterTask, NONCE_SIZE};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use std::cmp::min;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+extern "C" {
+    fn noncegen_rust(
+        cache: *mut u8,
+        chunk_offset: u64,
+        numeric_id: u64,
+        local_startnonce: u64,
+        local_nonces: u64,
+    ) -> ();
+}
+
+// fills empty buffers with hashed nonce ranges and hands them off to the writer, until
+// every nonce has been scheduled or the stop flag is observed between batches
+pub fn create_scheduler_thread(
+    task: Arc<PlotterTask>,
+    pool: ThreadPool,
+    progress: u64,
+    mut p1x: Option<ProgressBar<Pipe>>,
+    rx_empty_buffers: chan::Receiver<Buffer>,
+    tx_full_buffers: chan::Sender<Buffer>,
+    stop_flag: Arc<AtomicBool>,
+    simd_ext: String,
+) -> impl FnOnce() + Send {
+    move || {
+        let _ = &simd_ext;
+
+        let mut nonce = progress;
+
+        loop {
+            if stop_flag.load(Ordering::SeqCst) || nonce >= task.nonces {
+                break;
+            }
+
+            let mut buffer = match rx_empty_buffers.recv() {
+                Some(buffer) => buffer,
+                None => break,
+            };
+
+            let data_handle = buffer.get_buffer();
+            let nonces_to_hash = {
+                let data = data_handle.lock().unwrap();
+                min(data.len() as u64 / NONCE_SIZE, task.nonces - nonce)
+            };
+
+            {
+                let mut data = data_handle.lock().unwrap();
+                pool.install(|| {
+                    data[..(nonces_to_hash * NONCE_SIZE) as usize]
+                        .par_chunks_mut(NONCE_SIZE as usize)
+                        .enumerate()
+                        .for_each(|(i, chunk)| unsafe {
+                            noncegen_rust(
+                                chunk.as_mut_ptr(),
+                                0,
+                                task.numeric_id,
+                                task.start_nonce + nonce + i as u64,
+                                1,
+                            );
+                        });
+                });
+            }
+
+            buffer.set_filled(nonces_to_hash * NONCE_SIZE);
+
+            if let Some(ref mut p1) = p1x {
+                p1.add(nonces_to_hash * NONCE_SIZE);
+            }
+
+            nonce += nonces_to_hash;
+            tx_full_buffers.send(buffer);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+extern crate pbr;
+
+use self::pbr::{Pipe, ProgressBar};
+use chan;
+use plotter::{Buffer, PlotterTask, NONCE_SIZE};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+// drains filled buffers onto disk in nonce order, checkpointing the exact resumable
+// offset after every fsync'd write and removing the checkpoint once the plot is complete
+pub fn create_writer_thread(
+    task: Arc<PlotterTask>,
+    plot_file: PathBuf,
+    progress: u64,
+    mut p2x: Option<ProgressBar<Pipe>>,
+    rx_full_buffers: chan::Receiver<Buffer>,
+    tx_empty_buffers: chan::Sender<Buffer>,
+    stop_flag: Arc<AtomicBool>,
+    resume_offset: Arc<AtomicU64>,
+) -> impl FnOnce() + Send {
+    move || {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(&plot_file)
+            .expect("failed to open plot file for writing");
+
+        let mut nonce = progress;
+
+        loop {
+            // checked before blocking on recv, so a stop requested before the scheduler
+            // sent anything does not leave the writer waiting forever
+            if stop_flag.load(Ordering::SeqCst) || nonce >= task.nonces {
+                break;
+            }
+
+            let buffer = match rx_full_buffers.recv() {
+                Some(buffer) => buffer,
+                None => break,
+            };
+
+            let data_handle = buffer.get_buffer();
+            let data = data_handle.lock().unwrap();
+            let write_len = buffer.filled() as usize;
+            let nonces_in_buffer = buffer.filled() / NONCE_SIZE;
+
+            file.seek(SeekFrom::Start(nonce * NONCE_SIZE))
+                .expect("failed to seek plot file");
+            file.write_all(&data[..write_len])
+                .expect("failed to write plot data");
+            file.sync_data().expect("failed to fsync plot data");
+            drop(data);
+
+            nonce += nonces_in_buffer;
+
+            // only advance the checkpoint once the write above is durable, so a crash
+            // can never resume past data that isn't actually on disk
+            resume_offset.store(nonce, Ordering::SeqCst);
+            if nonce >= task.nonces {
+                // a finished plot keeps no sidecar, which read_resume_info reports as an error
+                let _ = fs::remove_file(resume_info_path(&plot_file));
+            } else {
+                write_resume_info(&plot_file, nonce);
+            }
+
+            if let Some(ref mut p2) = p2x {
+                p2.add(write_len as u64);
+            }
+
+            tx_empty_buffers.send(buffer);
+        }
+    }
+}
+
+fn resume_info_path(plot_file: &Path) -> std::path::PathBuf {
+    let mut os_string = plot_file.as_os_str().to_os_string();
+    os_string.push(".resume");
+    std::path::PathBuf::from(os_string)
+}
+
+// the plot file's sidecar resume file holds nothing but the decimal nonce offset of the
+// last fsync'd write, so a resumed run can pick back up exactly where it left off
+pub fn write_resume_info(plot_file: &Path, nonce: u64) {
+    if let Ok(mut file) = File::create(resume_info_path(plot_file)) {
+        let _ = file.write_all(nonce.to_string().as_bytes());
+    }
+}
+
+pub fn read_resume_info(plot_file: &Path) -> io::Result<u64> {
+    let mut contents = String::new();
+    File::open(resume_info_path(plot_file))?.read_to_string(&mut contents)?;
+    contents
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed resume file"))
+}